@@ -0,0 +1,569 @@
+//! Coordinates concurrent `git-wait` invocations in the same repo through a small
+//! client/server split over a Unix domain socket, so that N processes racing a lock
+//! don't all wake up and stampede `git` at once. The first invocation in a repo becomes
+//! the server: it owns an advisory lock, listens on `.git/git-wait.sock`, and dispatches
+//! queued commands one at a time, streaming each child's stdout/stderr back to whoever
+//! asked for it. Everyone else connects as a thin client and waits their turn.
+
+use crate::{GitDirs, GIT_WAIT_LOCK_FILE_NAME};
+use libc::{flock, LOCK_EX, LOCK_NB, LOCK_UN};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SOCKET_FILE_NAME: &str = "git-wait.sock";
+
+/// How long the server keeps draining its queue after finishing its own command before
+/// shutting down, to catch anyone who connected while it was busy.
+const SERVER_LINGER: Duration = Duration::from_millis(500);
+/// Give a freshly-elected server a moment to bind its socket before giving up.
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+const FRAME_STDOUT: u8 = b'O';
+const FRAME_STDERR: u8 = b'E';
+const FRAME_EXIT: u8 = b'X';
+/// Client -> server only. A zero-length `FRAME_STDIN` marks EOF on the client's stdin.
+const FRAME_STDIN: u8 = b'I';
+
+pub enum Outcome {
+    /// The daemon ran the command (locally, as the server, or via a client round-trip)
+    /// and it exited with this code.
+    Handled(i32),
+    /// No server is reachable and this process didn't become one either; the caller
+    /// should fall back to running git directly.
+    RunDirectly,
+}
+
+/// `timeout`, same as `GIT_WAIT_TIMEOUT_MS` everywhere else in this tool, bounds how
+/// long a client waits for its turn and its response; it does not bound the server's
+/// own command or anyone queued ahead of a given client.
+pub fn dispatch(
+    git_dirs: &GitDirs,
+    args: &[String],
+    timeout: Option<Duration>,
+) -> Result<Outcome, String> {
+    let socket_path = git_dirs.common_dir.join(SOCKET_FILE_NAME);
+    let cwd = match std::env::current_dir() {
+        Ok(cwd) => cwd,
+        Err(_) => return Ok(Outcome::RunDirectly),
+    };
+
+    if let Some(result) = run_as_client(&socket_path, &cwd, args, timeout) {
+        return result;
+    }
+
+    let lock_path = git_dirs.common_dir.join(GIT_WAIT_LOCK_FILE_NAME);
+    match try_become_server(&lock_path) {
+        Some(lock_file) => Ok(run_as_server(
+            &socket_path,
+            &lock_path,
+            lock_file,
+            cwd,
+            args.to_vec(),
+        )),
+        None => {
+            // Lost the race to become the server; give the new server a moment to
+            // bind its socket and try once more as a client before giving up.
+            thread::sleep(CONNECT_RETRY_DELAY);
+            run_as_client(&socket_path, &cwd, args, timeout).unwrap_or(Ok(Outcome::RunDirectly))
+        }
+    }
+}
+
+/// Whether a server is already up and listening for this repo. `maybe_wait_for_index_
+/// lock` uses this to skip its own raw lock-file wait when one is: that lock is very
+/// likely the one the daemon's own in-flight command is holding, and it'll clear the
+/// moment the daemon works through its queue to us, same as connecting as a client
+/// would tell us anyway -- there's no need to separately poll/watch for it here too.
+pub fn is_server_reachable(git_dirs: &GitDirs) -> bool {
+    let socket_path = git_dirs.common_dir.join(SOCKET_FILE_NAME);
+    UnixStream::connect(socket_path).is_ok()
+}
+
+fn try_become_server(lock_path: &Path) -> Option<File> {
+    let lock_file = File::create(lock_path).ok()?;
+    let locked = unsafe { flock(lock_file.as_raw_fd(), LOCK_EX | LOCK_NB) };
+    if locked == 0 {
+        Some(lock_file)
+    } else {
+        None
+    }
+}
+
+fn unlock(lock_file: &File) {
+    unsafe {
+        flock(lock_file.as_raw_fd(), LOCK_UN);
+    }
+}
+
+fn run_as_client(
+    socket_path: &Path,
+    cwd: &Path,
+    args: &[String],
+    timeout: Option<Duration>,
+) -> Option<Result<Outcome, String>> {
+    let mut stream = UnixStream::connect(socket_path).ok()?;
+    write_request(&mut stream, cwd, args).ok()?;
+
+    // Forward our real stdin to the server over a clone of the stream, in case the
+    // queued command needs it (e.g. `git commit` opening `$EDITOR`). Not joined: it
+    // ends once our stdin hits EOF or a write to the connection fails; while it is
+    // blocked reading stdin it stays blocked even after the server closes the connection.
+    if let Ok(stdin_stream) = stream.try_clone() {
+        thread::spawn(move || forward_stdin(stdin_stream));
+    }
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    Some(read_response(&mut stream, deadline).map(Outcome::Handled))
+}
+
+fn forward_stdin(mut stream: UnixStream) {
+    let mut stdin = std::io::stdin();
+    let mut buf = [0u8; 4096];
+    loop {
+        match stdin.read(&mut buf) {
+            Ok(0) | Err(_) => {
+                let _ = write_frame(&mut stream, FRAME_STDIN, &[]);
+                return;
+            }
+            Ok(n) => {
+                if write_frame(&mut stream, FRAME_STDIN, &buf[..n]).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn run_as_server(
+    socket_path: &Path,
+    lock_path: &Path,
+    lock_file: File,
+    cwd: PathBuf,
+    args: Vec<String>,
+) -> Outcome {
+    // A server that crashed leaves its socket file behind; a connect failure against it
+    // is how we detected we need a new server, so clear it before binding our own.
+    let _ = std::fs::remove_file(socket_path);
+
+    let listener = match UnixListener::bind(socket_path) {
+        Ok(listener) => listener,
+        Err(_) => return Outcome::RunDirectly,
+    };
+
+    let (tx, rx) = mpsc::channel::<(UnixStream, BufReader<UnixStream>, PathBuf, Vec<String>)>();
+    thread::spawn(move || accept_loop(listener, tx));
+
+    // Run our own command first, inheriting this process's stdio, so the invocation
+    // that happens to become the server behaves just like a direct one.
+    let exit_code = run_command_inherited(&cwd, &args);
+
+    // Then keep draining the queue for anyone who raced us, until it's quiet.
+    while let Ok((stream, reader, job_cwd, job_args)) = rx.recv_timeout(SERVER_LINGER) {
+        serve_client(stream, reader, &job_cwd, &job_args);
+    }
+
+    let _ = std::fs::remove_file(socket_path);
+    // Release the advisory lock before removing its file, not after, so a process
+    // that's already racing to become the next server can't observe a locked file
+    // that's also been unlinked out from under it.
+    unlock(&lock_file);
+    let _ = std::fs::remove_file(lock_path);
+
+    Outcome::Handled(exit_code)
+}
+
+fn accept_loop(
+    listener: UnixListener,
+    tx: mpsc::Sender<(UnixStream, BufReader<UnixStream>, PathBuf, Vec<String>)>,
+) {
+    for conn in listener.incoming() {
+        let stream = match conn {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+
+        // The request is read through a clone so `stream` stays free for writing
+        // responses back; the same clone is handed along to read stdin frames later,
+        // since any bytes already buffered past the request can't be recovered otherwise.
+        let mut reader = match stream.try_clone() {
+            Ok(clone) => BufReader::new(clone),
+            Err(_) => continue,
+        };
+
+        match read_request(&mut reader) {
+            Ok((cwd, args)) => {
+                if tx.send((stream, reader, cwd, args)).is_err() {
+                    break;
+                }
+            }
+            Err(_) => continue,
+        }
+    }
+}
+
+fn serve_client(mut stream: UnixStream, reader: BufReader<UnixStream>, cwd: &Path, args: &[String]) {
+    let exit_code = run_command_streamed(cwd, args, &mut stream, reader);
+    let _ = write_exit_frame(&mut stream, exit_code);
+}
+
+fn run_command_inherited(cwd: &Path, args: &[String]) -> i32 {
+    match Command::new(&args[0]).args(&args[1..]).current_dir(cwd).status() {
+        Ok(status) => status.code().unwrap_or(1),
+        Err(e) => {
+            eprintln!("ERROR: error executing git: {}", e);
+            1
+        }
+    }
+}
+
+enum Chunk {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+}
+
+fn run_command_streamed(
+    cwd: &Path,
+    args: &[String],
+    stream: &mut UnixStream,
+    stdin_reader: BufReader<UnixStream>,
+) -> i32 {
+    let mut child = match Command::new(&args[0])
+        .args(&args[1..])
+        .current_dir(cwd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            let _ = write_frame(
+                stream,
+                FRAME_STDERR,
+                format!("error executing git: {}\n", e).as_bytes(),
+            );
+            return 1;
+        }
+    };
+
+    let mut child_stdin = child.stdin.take().unwrap();
+    let mut child_stdout = child.stdout.take().unwrap();
+    let mut child_stderr = child.stderr.take().unwrap();
+
+    // Not joined: a command that never reads stdin would otherwise leave us blocked
+    // here forever waiting on FRAME_STDIN frames that will never arrive. It unblocks
+    // on its own once the client's stdin hits EOF or the connection closes.
+    let mut stdin_reader = stdin_reader;
+    thread::spawn(move || forward_stdin_frames(&mut stdin_reader, &mut child_stdin));
+
+    // Both pipes are pumped from their own thread, but writes to `stream` all happen
+    // here so stdout/stderr frames can't interleave mid-frame.
+    let (tx, rx) = mpsc::channel::<Chunk>();
+
+    let stdout_tx = tx.clone();
+    let stdout_handle = thread::spawn(move || pump(&mut child_stdout, stdout_tx, Chunk::Stdout));
+    let stderr_handle = thread::spawn(move || pump(&mut child_stderr, tx, Chunk::Stderr));
+
+    for chunk in rx {
+        let result = match chunk {
+            Chunk::Stdout(data) => write_frame(stream, FRAME_STDOUT, &data),
+            Chunk::Stderr(data) => write_frame(stream, FRAME_STDERR, &data),
+        };
+        if result.is_err() {
+            break;
+        }
+    }
+
+    let _ = stdout_handle.join();
+    let _ = stderr_handle.join();
+
+    match child.wait() {
+        Ok(status) => status.code().unwrap_or(1),
+        Err(_) => 1,
+    }
+}
+
+/// Relays `FRAME_STDIN` frames read from the client connection into the child's stdin,
+/// until EOF (a zero-length frame), a protocol error, or the connection closing.
+fn forward_stdin_frames(reader: &mut BufReader<UnixStream>, child_stdin: &mut impl Write) {
+    loop {
+        let mut header = [0u8; 5];
+        if reader.read_exact(&mut header).is_err() {
+            return;
+        }
+
+        let tag = header[0];
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if tag != FRAME_STDIN || len == 0 {
+            return;
+        }
+
+        let mut buf = vec![0u8; len];
+        if reader.read_exact(&mut buf).is_err() || child_stdin.write_all(&buf).is_err() {
+            return;
+        }
+    }
+}
+
+fn pump(source: &mut impl Read, tx: mpsc::Sender<Chunk>, wrap: fn(Vec<u8>) -> Chunk) {
+    let mut buf = [0u8; 4096];
+    loop {
+        match source.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => {
+                if tx.send(wrap(buf[..n].to_vec())).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn write_request(stream: &mut UnixStream, cwd: &Path, args: &[String]) -> std::io::Result<()> {
+    writeln!(stream, "{}", cwd.display())?;
+    writeln!(stream, "{}", args.len())?;
+    for arg in args {
+        writeln!(stream, "{}", arg)?;
+    }
+    Ok(())
+}
+
+fn read_request(reader: &mut BufReader<UnixStream>) -> std::io::Result<(PathBuf, Vec<String>)> {
+    let mut cwd_line = String::new();
+    reader.read_line(&mut cwd_line)?;
+    let cwd = PathBuf::from(cwd_line.trim_end_matches('\n'));
+
+    let mut count_line = String::new();
+    reader.read_line(&mut count_line)?;
+    let count: usize = count_line
+        .trim()
+        .parse()
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "bad arg count"))?;
+
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        args.push(line.trim_end_matches('\n').to_string());
+    }
+
+    Ok((cwd, args))
+}
+
+fn write_frame(stream: &mut UnixStream, tag: u8, data: &[u8]) -> std::io::Result<()> {
+    let mut header = [0u8; 5];
+    header[0] = tag;
+    header[1..].copy_from_slice(&(data.len() as u32).to_be_bytes());
+    stream.write_all(&header)?;
+    stream.write_all(data)
+}
+
+fn write_exit_frame(stream: &mut UnixStream, code: i32) -> std::io::Result<()> {
+    let mut header = [0u8; 5];
+    header[0] = FRAME_EXIT;
+    header[1..].copy_from_slice(&(code as u32).to_be_bytes());
+    stream.write_all(&header)
+}
+
+/// Reads frames until `FRAME_EXIT`, bounded by `deadline` if one was given. A command
+/// that's still running when the deadline passes keeps running on the server (we just
+/// stop waiting for it), same as any other timed-out wait in this tool.
+fn read_response(stream: &mut UnixStream, deadline: Option<Instant>) -> Result<i32, String> {
+    loop {
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err("timed out!".to_string());
+            }
+            let _ = stream.set_read_timeout(Some(remaining));
+        }
+
+        let mut header = [0u8; 5];
+        if let Err(e) = stream.read_exact(&mut header) {
+            return if is_timeout(&e) {
+                Err("timed out!".to_string())
+            } else {
+                Ok(1)
+            };
+        }
+
+        let tag = header[0];
+        let value = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+
+        match tag {
+            FRAME_STDOUT | FRAME_STDERR => {
+                let mut buf = vec![0u8; value as usize];
+                if let Err(e) = stream.read_exact(&mut buf) {
+                    return if is_timeout(&e) {
+                        Err("timed out!".to_string())
+                    } else {
+                        Ok(1)
+                    };
+                }
+                if tag == FRAME_STDOUT {
+                    let _ = std::io::stdout().write_all(&buf);
+                    let _ = std::io::stdout().flush();
+                } else {
+                    let _ = std::io::stderr().write_all(&buf);
+                    let _ = std::io::stderr().flush();
+                }
+            }
+            FRAME_EXIT => return Ok(value as i32),
+            _ => return Ok(1),
+        }
+    }
+}
+
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dispatch, Outcome};
+    use crate::GitDirs;
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn test_repo_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("git-wait-daemon-test-{}", name));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn test_git_dirs(dir: std::path::PathBuf) -> GitDirs {
+        GitDirs {
+            worktree_dir: dir.clone(),
+            common_dir: dir,
+        }
+    }
+
+    #[test]
+    fn is_server_reachable_reflects_whether_a_listener_is_bound() {
+        let dir = test_repo_dir("reachable");
+        let git_dirs = test_git_dirs(dir.clone());
+
+        assert!(!super::is_server_reachable(&git_dirs));
+
+        let socket_path = dir.join(super::SOCKET_FILE_NAME);
+        let _listener = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+        assert!(super::is_server_reachable(&git_dirs));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn first_invocation_becomes_the_server_and_runs_its_own_command() {
+        let dir = test_repo_dir("solo");
+        let git_dirs = test_git_dirs(dir.clone());
+
+        let outcome = dispatch(&git_dirs, &["echo".to_string(), "hello".to_string()], None);
+        assert!(matches!(outcome, Ok(Outcome::Handled(0))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_invocations_are_serialized_through_one_daemon() {
+        let dir = test_repo_dir("concurrent");
+        let git_dirs = Arc::new(test_git_dirs(dir.clone()));
+
+        let gd1 = Arc::clone(&git_dirs);
+        let first = thread::spawn(move || {
+            dispatch(
+                &gd1,
+                &[
+                    "sh".to_string(),
+                    "-c".to_string(),
+                    "sleep 0.1; echo first".to_string(),
+                ],
+                None,
+            )
+        });
+
+        // Give the first invocation time to win the race and become the server.
+        thread::sleep(Duration::from_millis(30));
+
+        let gd2 = Arc::clone(&git_dirs);
+        let second = thread::spawn(move || {
+            dispatch(&gd2, &["echo".to_string(), "second".to_string()], None)
+        });
+
+        assert!(matches!(first.join().unwrap(), Ok(Outcome::Handled(0))));
+        assert!(matches!(second.join().unwrap(), Ok(Outcome::Handled(0))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn forward_stdin_frames_relays_bytes_until_eof_marker() {
+        let dir = test_repo_dir("stdin-frames");
+        let socket_path = dir.join("test.sock");
+
+        let listener = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+        let writer_path = socket_path.clone();
+        let writer = thread::spawn(move || {
+            let mut stream = std::os::unix::net::UnixStream::connect(&writer_path).unwrap();
+            super::write_frame(&mut stream, super::FRAME_STDIN, b"hello ").unwrap();
+            super::write_frame(&mut stream, super::FRAME_STDIN, b"world").unwrap();
+            super::write_frame(&mut stream, super::FRAME_STDIN, &[]).unwrap();
+        });
+
+        let (server_stream, _) = listener.accept().unwrap();
+        let mut reader = std::io::BufReader::new(server_stream);
+        let mut sink: Vec<u8> = Vec::new();
+        super::forward_stdin_frames(&mut reader, &mut sink);
+
+        writer.join().unwrap();
+        assert_eq!(sink, b"hello world");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn client_times_out_waiting_behind_a_long_running_queued_command() {
+        let dir = test_repo_dir("client-timeout");
+        let git_dirs = Arc::new(test_git_dirs(dir.clone()));
+
+        let gd1 = Arc::clone(&git_dirs);
+        let first = thread::spawn(move || {
+            dispatch(
+                &gd1,
+                &["sh".to_string(), "-c".to_string(), "sleep 0.3".to_string()],
+                None,
+            )
+        });
+
+        // Give the first invocation time to win the race and become the server.
+        thread::sleep(Duration::from_millis(30));
+
+        let gd2 = Arc::clone(&git_dirs);
+        let second = thread::spawn(move || {
+            dispatch(
+                &gd2,
+                &["echo".to_string(), "second".to_string()],
+                Some(Duration::from_millis(50)),
+            )
+        });
+
+        assert!(matches!(second.join().unwrap(), Err(ref e) if e == "timed out!"));
+        assert!(matches!(first.join().unwrap(), Ok(Outcome::Handled(0))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,32 +1,139 @@
+mod daemon;
+
 use errno::errno;
-use libc::execvp;
+use libc::{execvp, isatty, STDERR_FILENO};
 use notify::event::RemoveKind;
 use notify::{Config, ErrorKind, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
-use std::ffi::CString;
-use std::io::{stdout, Write};
+use std::ffi::{CString, OsStr};
+use std::io::{stderr, Write};
 use std::path::PathBuf;
 use std::sync::mpsc;
 use std::sync::mpsc::RecvTimeoutError;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{env::current_dir, path::Path, ptr};
 
-const INDEX_LOCK_NAME: &'static str = "index.lock";
 const GIT_DIR_NAME: &'static str = ".git";
+const GITDIR_FILE_PREFIX: &'static str = "gitdir:";
+const COMMONDIR_FILE_NAME: &'static str = "commondir";
+const LOCK_EXTENSION: &'static str = "lock";
+const OBJECTS_DIR_NAME: &'static str = "objects";
+const WORKTREES_DIR_NAME: &'static str = "worktrees";
 const TIMEOUT_ENV_VAR: &'static str = "GIT_WAIT_TIMEOUT_MS";
+const POLL_ENV_VAR: &'static str = "GIT_WAIT_POLL";
+const POLL_INTERVAL_ENV_VAR: &'static str = "GIT_WAIT_POLL_INTERVAL_MS";
+const DEFAULT_POLL_INTERVAL_MS: u64 = 100;
+const CONFIG_FILE_NAME: &'static str = "git-wait.toml";
+const WAIT_FOR_ENV_VAR: &'static str = "GIT_WAIT_FOR";
+const SKIP_ENV_VAR: &'static str = "GIT_WAIT_SKIP";
+/// git-wait's own daemon coordination file (see `daemon.rs`). It lives under the same
+/// `.git` tree the lock scan walks and happens to end in `.lock`, so it must be excluded
+/// from `is_lock_file` or the daemon would wait on its own bookkeeping forever.
+pub(crate) const GIT_WAIT_LOCK_FILE_NAME: &'static str = "git-wait.lock";
+const SPINNER_FRAMES: &[char] = &['|', '/', '-', '\\'];
+const SPINNER_TICK: Duration = Duration::from_millis(100);
 
-fn main() {
-    let args = std::env::args().collect::<Vec<_>>();
-    match maybe_wait_for_index_lock(args) {
-        Ok(args) => {
-            if let Err(e) = run_git_cmd(&args) {
-                eprintln!("ERROR: {}", e);
-                std::process::exit(1);
-            }
+/// Subcommands that never touch the index or refs, so there's no point waiting on a
+/// lock held by an unrelated write in progress.
+const DEFAULT_READ_ONLY_SUBCOMMANDS: &[&str] = &[
+    "log",
+    "show",
+    "diff",
+    "rev-parse",
+    "status",
+    "blame",
+    "cat-file",
+    "describe",
+    "ls-files",
+    "ls-tree",
+    "shortlog",
+    "reflog",
+];
+
+/// `wait_for`/`skip` overrides loaded from `git-wait.toml` and the `GIT_WAIT_FOR`/
+/// `GIT_WAIT_SKIP` env vars. `wait_for` takes priority over `skip`, which in turn
+/// overrides `DEFAULT_READ_ONLY_SUBCOMMANDS`.
+#[derive(Default)]
+struct CommandConfig {
+    wait_for: Vec<String>,
+    skip: Vec<String>,
+}
+
+/// The directories that actually matter for lock contention. `worktree_dir` is where
+/// `index.lock` and `HEAD.lock` live; for linked worktrees and submodules this is
+/// `.git/worktrees/<name>` (or `.git/modules/<name>`) rather than a top-level `.git`.
+/// `common_dir` is where shared state lives (`packed-refs.lock`, `config.lock`,
+/// `objects/`) and is equal to `worktree_dir` except in linked worktrees.
+struct GitDirs {
+    worktree_dir: PathBuf,
+    common_dir: PathBuf,
+}
+
+impl GitDirs {
+    fn watch_paths(&self) -> Vec<&Path> {
+        if self.common_dir == self.worktree_dir {
+            vec![&self.worktree_dir]
+        } else {
+            vec![&self.worktree_dir, &self.common_dir]
         }
-        Err(e) => {
-            eprintln!("ERROR: {}", e);
-            std::process::exit(1);
+    }
+
+    fn find_lock_files(&self) -> Vec<PathBuf> {
+        let mut locks = find_lock_files(&self.worktree_dir);
+        if self.common_dir != self.worktree_dir {
+            // `collect_lock_files` never descends into `worktrees/` (every worktree
+            // is scanned on its own, via the direct `worktree_dir` call above), so
+            // this can't turn up our own locks again -- or, just as importantly, a
+            // lock held by a sibling worktree that has nothing to do with us.
+            locks.extend(find_lock_files(&self.common_dir));
         }
+        locks
+    }
+}
+
+fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    if let Err(e) = run(args) {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: Vec<String>) -> Result<(), String> {
+    let args = maybe_wait_for_index_lock(args)?;
+    let timeout = read_timeout_env_var()?;
+
+    match daemon_outcome_for(&args, timeout)? {
+        daemon::Outcome::Handled(exit_code) => std::process::exit(exit_code),
+        daemon::Outcome::RunDirectly => run_git_cmd(&args),
+    }
+}
+
+/// Routes a command through the per-repo daemon when it's worth coordinating (the same
+/// commands that would otherwise wait on a lock); read-only commands run directly since
+/// there's nothing to serialize them against. `timeout` bounds a client's wait behind
+/// whatever's already queued ahead of it, same as `GIT_WAIT_TIMEOUT_MS` bounds `wait()`.
+fn daemon_outcome_for(
+    args: &[String],
+    timeout: Option<Duration>,
+) -> Result<daemon::Outcome, String> {
+    let git_dirs = match resolve_current_git_dirs() {
+        Some(git_dirs) => git_dirs,
+        None => return Ok(daemon::Outcome::RunDirectly),
+    };
+
+    if !should_wait_for_command(args.get(1).map(String::as_str), &git_dirs) {
+        return Ok(daemon::Outcome::RunDirectly);
+    }
+
+    daemon::dispatch(&git_dirs, args, timeout)
+}
+
+fn resolve_current_git_dirs() -> Option<GitDirs> {
+    let mut dir = current_dir().ok()?;
+    if traverse_to_git_dir(&mut dir) {
+        resolve_git_dirs(&dir).ok()
+    } else {
+        None
     }
 }
 
@@ -34,26 +141,125 @@ fn maybe_wait_for_index_lock(mut args: Vec<String>) -> Result<Vec<String>, Strin
     args[0] = "git".to_string();
 
     let mut dir = current_dir().map_err(|_| "unable to read current directory.".to_string())?;
-    // Find .git dir.
+    // Find .git entry (a directory in a normal checkout, a file in worktrees/submodules).
     if traverse_to_git_dir(&mut dir) {
-        let timeout = read_timeout_env_var()?;
-
-        let index_lock_path = dir.join(INDEX_LOCK_NAME);
-        if index_lock_path.exists() {
-            print!("waiting on index.lock... ");
-            stdout().flush().unwrap();
-            wait(&index_lock_path, timeout)?;
-            println!("done!");
-            Ok(args)
-        } else {
-            Ok(args)
+        let git_dirs = resolve_git_dirs(&dir)?;
+        let subcommand = args.get(1).map(String::as_str);
+
+        if should_wait_for_command(subcommand, &git_dirs) {
+            // If a daemon is already up for this repo, let it queue this command
+            // instead: waiting on the raw lock file here independently is how a
+            // staggered overlap (one client arrives while another is already
+            // running through the daemon) ends up re-waiting on a lock the daemon
+            // is about to clear for us anyway, instead of just taking its turn.
+            if !daemon::is_server_reachable(&git_dirs) {
+                let timeout = read_timeout_env_var()?;
+
+                if !git_dirs.find_lock_files().is_empty() {
+                    wait(&git_dirs, timeout)?;
+                }
+            }
         }
+    }
+
+    // Run the git command either way.
+    Ok(args)
+}
+
+fn should_wait_for_command(subcommand: Option<&str>, git_dirs: &GitDirs) -> bool {
+    let subcommand = match subcommand {
+        Some(subcommand) => subcommand,
+        None => return true,
+    };
+
+    let config = load_command_config(git_dirs);
+
+    if config.wait_for.iter().any(|s| s == subcommand) {
+        true
+    } else if config.skip.iter().any(|s| s == subcommand) {
+        false
     } else {
-        // Run the git command anyway!
-        Ok(args)
+        !DEFAULT_READ_ONLY_SUBCOMMANDS.contains(&subcommand)
     }
 }
 
+fn load_command_config(git_dirs: &GitDirs) -> CommandConfig {
+    let mut config = CommandConfig::default();
+
+    if let Some(path) = config_file_path(git_dirs) {
+        if let Ok(contents) = std::fs::read_to_string(&path) {
+            merge_config_file(&contents, &mut config);
+        }
+    }
+
+    merge_env_list(WAIT_FOR_ENV_VAR, &mut config.wait_for);
+    merge_env_list(SKIP_ENV_VAR, &mut config.skip);
+
+    config
+}
+
+fn config_file_path(git_dirs: &GitDirs) -> Option<PathBuf> {
+    let repo_config = git_dirs.common_dir.join(CONFIG_FILE_NAME);
+    if repo_config.exists() {
+        return Some(repo_config);
+    }
+
+    let config_home = std::env::var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| std::env::var("HOME").map(|home| Path::new(&home).join(".config")))
+        .ok()?;
+
+    let user_config = config_home.join("git-wait").join("config.toml");
+    if user_config.exists() {
+        Some(user_config)
+    } else {
+        None
+    }
+}
+
+fn merge_env_list(var: &str, into: &mut Vec<String>) {
+    if let Ok(value) = std::env::var(var) {
+        into.extend(
+            value
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty()),
+        );
+    }
+}
+
+/// Parses the small subset of TOML git-wait's config needs: top-level
+/// `wait_for = ["a", "b"]` / `skip = ["a", "b"]` array-of-strings assignments.
+fn merge_config_file(contents: &str, config: &mut CommandConfig) {
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some(parts) => parts,
+            None => continue,
+        };
+
+        match key.trim() {
+            "wait_for" => config.wait_for.extend(parse_string_array(value.trim())),
+            "skip" => config.skip.extend(parse_string_array(value.trim())),
+            _ => {}
+        }
+    }
+}
+
+fn parse_string_array(value: &str) -> Vec<String> {
+    value
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .split(',')
+        .map(|s| s.trim().trim_matches('"').to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
 fn read_timeout_env_var() -> Result<Option<Duration>, String> {
     if let Ok(timeout) = std::env::var(TIMEOUT_ENV_VAR) {
         let timeout = timeout
@@ -65,6 +271,24 @@ fn read_timeout_env_var() -> Result<Option<Duration>, String> {
     }
 }
 
+fn should_poll() -> bool {
+    std::env::var(POLL_ENV_VAR)
+        .map(|v| v == "1")
+        .unwrap_or(false)
+}
+
+fn read_poll_interval_env_var() -> Result<Duration, String> {
+    match std::env::var(POLL_INTERVAL_ENV_VAR) {
+        Ok(interval) => {
+            let interval = interval
+                .parse()
+                .map_err(|e| format!("poll interval parse error: {}", e))?;
+            Ok(Duration::from_millis(interval))
+        }
+        Err(_) => Ok(Duration::from_millis(DEFAULT_POLL_INTERVAL_MS)),
+    }
+}
+
 fn traverse_to_git_dir(dir: &mut PathBuf) -> bool {
     loop {
         dir.push(GIT_DIR_NAME);
@@ -81,6 +305,62 @@ fn traverse_to_git_dir(dir: &mut PathBuf) -> bool {
     }
 }
 
+/// Resolves the real git dir(s) behind a `.git` entry. The entry is a directory in a
+/// normal checkout, but a file containing `gitdir: <path>` in linked worktrees and
+/// submodules, so `dir.join(...)` on it would silently point inside a regular file.
+fn resolve_git_dirs(git_entry: &Path) -> Result<GitDirs, String> {
+    let worktree_dir = if git_entry.is_file() {
+        resolve_gitdir_file(git_entry)?
+    } else {
+        git_entry.to_path_buf()
+    };
+
+    let common_dir = resolve_common_dir(&worktree_dir)?;
+
+    Ok(GitDirs {
+        worktree_dir,
+        common_dir,
+    })
+}
+
+fn resolve_gitdir_file(path: &Path) -> Result<PathBuf, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+    let gitdir = contents
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix(GITDIR_FILE_PREFIX))
+        .map(|s| s.trim())
+        .ok_or_else(|| format!("malformed gitdir file: {}", path.display()))?;
+
+    Ok(resolve_relative_to(path.parent(), gitdir))
+}
+
+/// Linked worktrees keep a `commondir` file inside their git dir pointing back at the
+/// shared `.git` dir; everything else (a normal repo, a submodule) has none and is its
+/// own common dir.
+fn resolve_common_dir(worktree_dir: &Path) -> Result<PathBuf, String> {
+    let commondir_file = worktree_dir.join(COMMONDIR_FILE_NAME);
+    if !commondir_file.exists() {
+        return Ok(worktree_dir.to_path_buf());
+    }
+
+    let contents = std::fs::read_to_string(&commondir_file)
+        .map_err(|e| format!("unable to read {}: {}", commondir_file.display(), e))?;
+    let common = contents.lines().next().unwrap_or("").trim();
+
+    Ok(resolve_relative_to(Some(worktree_dir), common))
+}
+
+fn resolve_relative_to(base: Option<&Path>, path: &str) -> PathBuf {
+    let path = PathBuf::from(path);
+    if path.is_absolute() {
+        path
+    } else {
+        base.unwrap_or(Path::new(".")).join(path)
+    }
+}
+
 fn run_git_cmd(args: &[String]) -> Result<(), String> {
     // Unwrapping is fine here since the first arg is "git".
     let program_name = CString::new(args[0].as_bytes()).unwrap();
@@ -105,57 +385,298 @@ fn run_git_cmd(args: &[String]) -> Result<(), String> {
     }
 }
 
-fn wait(path: &Path, timeout: Option<Duration>) -> Result<(), String> {
+/// Draws an animated "waiting on <lock> (Ns)" line on stderr while `wait` blocks, and
+/// erases it again once the wait is over. A no-op when stderr isn't a TTY, so CI logs
+/// and piped output never see the spinner's carriage returns.
+struct Spinner {
+    enabled: bool,
+    start: Instant,
+    frame: usize,
+    last_len: usize,
+}
+
+impl Spinner {
+    fn new() -> Self {
+        Spinner {
+            enabled: stderr_is_tty(),
+            start: Instant::now(),
+            frame: 0,
+            last_len: 0,
+        }
+    }
+
+    fn tick(&mut self, locks: &[PathBuf]) {
+        if !self.enabled {
+            return;
+        }
+
+        let line = format!(
+            "\r{} waiting on {} ({}s)",
+            SPINNER_FRAMES[self.frame % SPINNER_FRAMES.len()],
+            describe_locks(locks),
+            self.start.elapsed().as_secs(),
+        );
+        self.frame = self.frame.wrapping_add(1);
+
+        let padding = " ".repeat(self.last_len.saturating_sub(line.len() - 1));
+        let mut stderr = stderr();
+        let _ = write!(stderr, "{}{}", line, padding);
+        let _ = stderr.flush();
+        self.last_len = line.len() - 1;
+    }
+
+    fn clear(&mut self) {
+        if !self.enabled || self.last_len == 0 {
+            return;
+        }
+
+        let mut stderr = stderr();
+        let _ = write!(stderr, "\r{}\r", " ".repeat(self.last_len));
+        let _ = stderr.flush();
+        self.last_len = 0;
+    }
+}
+
+fn stderr_is_tty() -> bool {
+    unsafe { isatty(STDERR_FILENO) == 1 }
+}
+
+/// Describes the lock(s) being waited on, e.g. `index.lock` or `index.lock (+2 more)`.
+fn describe_locks(locks: &[PathBuf]) -> String {
+    match locks.split_first() {
+        None => "lock".to_string(),
+        Some((first, rest)) => {
+            let name = first
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| first.to_string_lossy().into_owned());
+            if rest.is_empty() {
+                name
+            } else {
+                format!("{} (+{} more)", name, rest.len())
+            }
+        }
+    }
+}
+
+fn wait(git_dirs: &GitDirs, timeout: Option<Duration>) -> Result<(), String> {
+    // A lock may already be gone by the time we get here.
+    if git_dirs.find_lock_files().is_empty() {
+        return Ok(());
+    }
+
+    let mut spinner = Spinner::new();
+
+    let result = if should_poll() {
+        wait_by_polling(git_dirs, timeout, &mut spinner)
+    } else {
+        match wait_by_watching(git_dirs, timeout, &mut spinner) {
+            WatchOutcome::Done(result) => result,
+            // The watcher backend can't be used here (e.g. an exhausted inotify watch
+            // limit or a filesystem that doesn't support notifications); poll instead.
+            WatchOutcome::Unsupported => wait_by_polling(git_dirs, timeout, &mut spinner),
+        }
+    };
+
+    spinner.clear();
+    result
+}
+
+enum WatchOutcome {
+    Done(Result<(), String>),
+    Unsupported,
+}
+
+fn wait_by_watching(
+    git_dirs: &GitDirs,
+    timeout: Option<Duration>,
+    spinner: &mut Spinner,
+) -> WatchOutcome {
     let (tx, rx) = mpsc::channel::<Event>();
 
-    let mut watcher = RecommendedWatcher::new(
+    let mut watcher = match RecommendedWatcher::new(
         move |res| {
             if let Ok(event) = res {
                 tx.send(event).unwrap();
             }
         },
         Config::default(),
-    )
-    .map_err(|e| format!("unable to initialize file watcher: {}", e))?;
-
-    if let Err(e) = watcher.watch(path, RecursiveMode::NonRecursive) {
-        return match e.kind {
-            ErrorKind::PathNotFound => {
-                // index.lock no longer exists at this point.
-                Ok(())
+    ) {
+        Ok(watcher) => watcher,
+        Err(_) => return WatchOutcome::Unsupported,
+    };
+
+    // A single `RecursiveMode::Recursive` watch on each root would walk straight into
+    // `objects/` and every sibling worktree's own directory, the same subtrees
+    // `collect_lock_files` already knows to skip -- registering inotify watches there
+    // is pure waste and eats into the same watch-limit budget the polling fallback
+    // below exists to route around. Watch each non-excluded subdirectory on its own
+    // instead, mirroring that scan exactly.
+    let mut watched = 0;
+    for root in git_dirs.watch_paths() {
+        for dir in watchable_subdirs(root) {
+            match watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                Ok(()) => watched += 1,
+                Err(e) => match e.kind {
+                    // This dir no longer exists at this point; keep trying the rest.
+                    ErrorKind::PathNotFound => {}
+                    _ => return WatchOutcome::Unsupported,
+                },
             }
-            _ => Err(format!("unable to watch index.lock: {}", e)),
-        };
+        }
     }
+    if watched == 0 {
+        return WatchOutcome::Done(Ok(()));
+    }
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
 
     loop {
-        if let Some(timeout) = timeout {
-            match rx.recv_timeout(timeout) {
-                Ok(event) => {
-                    if event.kind == EventKind::Remove(RemoveKind::File) {
-                        return Ok(());
-                    }
-                }
-                Err(RecvTimeoutError::Timeout) => {
-                    return Err("timed out!".to_string());
-                }
-                Err(RecvTimeoutError::Disconnected) => {
-                    return Err("broken channel".to_string());
+        spinner.tick(&git_dirs.find_lock_files());
+
+        // Wake up on our own schedule too, not just on notify events, so the spinner
+        // keeps animating and the elapsed-time display stays live during a long wait.
+        let tick = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return WatchOutcome::Done(Err("timed out!".to_string()));
                 }
+                SPINNER_TICK.min(remaining)
             }
-        } else {
-            for event in &rx {
-                if event.kind == EventKind::Remove(RemoveKind::File) {
-                    return Ok(());
+            None => SPINNER_TICK,
+        };
+
+        match rx.recv_timeout(tick) {
+            Ok(event) => {
+                // Ignore anything that isn't a lock file being removed; re-scan on those
+                // since another lock can appear (or still be held) while we were waking up.
+                if event.kind == EventKind::Remove(RemoveKind::File) && is_lock_event(&event) {
+                    if git_dirs.find_lock_files().is_empty() {
+                        return WatchOutcome::Done(Ok(()));
+                    }
                 }
             }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return WatchOutcome::Done(Err("broken channel".to_string()))
+            }
+        }
+    }
+}
+
+fn wait_by_polling(
+    git_dirs: &GitDirs,
+    timeout: Option<Duration>,
+    spinner: &mut Spinner,
+) -> Result<(), String> {
+    let interval = read_poll_interval_env_var()?;
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    let mut locks = git_dirs.find_lock_files();
+    let mut last_scan = Instant::now();
+
+    loop {
+        if locks.is_empty() {
+            return Ok(());
+        }
+        spinner.tick(&locks);
+
+        if let Some(deadline) = deadline {
+            if Instant::now() >= deadline {
+                return Err("timed out!".to_string());
+            }
+        }
+
+        // Sleep in spinner-sized slices so the animation still ticks even when the
+        // configured poll interval is long, but only rescan the filesystem once per
+        // `interval` -- that's the whole point of the option on repos backed by slow
+        // network filesystems, where a directory walk every 100ms defeats it.
+        std::thread::sleep(interval.min(SPINNER_TICK));
+
+        if last_scan.elapsed() >= interval {
+            locks = git_dirs.find_lock_files();
+            last_scan = Instant::now();
+        }
+    }
+}
+
+fn is_lock_event(event: &Event) -> bool {
+    event.paths.iter().any(|path| is_lock_file(path))
+}
+
+fn is_lock_file(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new(LOCK_EXTENSION))
+        && path.file_name() != Some(OsStr::new(GIT_WAIT_LOCK_FILE_NAME))
+}
+
+/// `root` plus every descendant directory worth an inotify watch, excluding the same
+/// subtrees `collect_lock_files` excludes (see `is_excluded_subdir`). Used to register
+/// one non-recursive watch per directory instead of a single recursive one, so those
+/// exclusions actually keep inotify from being asked to watch inside them.
+fn watchable_subdirs(root: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![root.to_path_buf()];
+    let mut queue = vec![root.to_path_buf()];
+
+    while let Some(dir) = queue.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !path.is_dir() || is_excluded_subdir(&path) {
+                continue;
+            }
+            queue.push(path.clone());
+            dirs.push(path);
+        }
+    }
+
+    dirs
+}
+
+fn find_lock_files(git_dir: &Path) -> Vec<PathBuf> {
+    let mut locks = Vec::new();
+    collect_lock_files(git_dir, &mut locks);
+    locks
+}
+
+fn collect_lock_files(dir: &Path, locks: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            if is_excluded_subdir(&path) {
+                continue;
+            }
+            collect_lock_files(&path, locks);
+        } else if is_lock_file(&path) {
+            locks.push(path);
         }
     }
 }
 
+/// Subdirectories that are never worth walking into: `objects/` can be huge and is
+/// never lock-bearing, and each worktree under `worktrees/` is already covered on its
+/// own via `GitDirs`'s direct `worktree_dir` entry, so walking into it here too would
+/// count one worktree's lock against every other worktree sharing this common dir.
+fn is_excluded_subdir(path: &Path) -> bool {
+    path.file_name() == Some(OsStr::new(OBJECTS_DIR_NAME))
+        || path.file_name() == Some(OsStr::new(WORKTREES_DIR_NAME))
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{maybe_wait_for_index_lock, traverse_to_git_dir};
+    use crate::{
+        describe_locks, find_lock_files, maybe_wait_for_index_lock, resolve_git_dirs,
+        traverse_to_git_dir, watchable_subdirs, GitDirs,
+    };
     use lazy_static::lazy_static;
     use std::env::current_dir;
     use std::fs::File;
@@ -201,7 +722,7 @@ mod tests {
             let (tx, rx) = mpsc::channel::<Result<Vec<String>, String>>();
             let handle = std::thread::spawn(move || {
                 let result =
-                    maybe_wait_for_index_lock(vec!["git".to_string(), "status".to_string()]);
+                    maybe_wait_for_index_lock(vec!["git".to_string(), "commit".to_string()]);
                 tx.send(result).unwrap();
             });
 
@@ -219,6 +740,266 @@ mod tests {
         });
     }
 
+    #[test]
+    fn wait_if_other_lock_is_present() {
+        with_test_dir(|test_dir| {
+            fs::create_dir(&test_dir.path.join(".git")).unwrap();
+
+            // Create a ref lock file rather than index.lock.
+            let head_lock = test_dir.path.join(".git/HEAD.lock");
+            let _ = File::create(&head_lock).unwrap();
+
+            let (tx, rx) = mpsc::channel::<Result<Vec<String>, String>>();
+            let handle = std::thread::spawn(move || {
+                let result =
+                    maybe_wait_for_index_lock(vec!["git".to_string(), "commit".to_string()]);
+                tx.send(result).unwrap();
+            });
+
+            let result = rx.recv_timeout(Duration::from_millis(100));
+            assert_eq!(result, Err(RecvTimeoutError::Timeout));
+
+            fs::remove_file(head_lock).unwrap();
+
+            let result = rx.recv_timeout(Duration::from_millis(200));
+            assert!(result.unwrap().is_ok());
+
+            handle.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn wait_by_polling_until_lock_is_removed() {
+        with_test_dir(|test_dir| {
+            fs::create_dir(&test_dir.path.join(".git")).unwrap();
+
+            let index_file = test_dir.path.join(".git/index.lock");
+            let _ = File::create(&index_file).unwrap();
+
+            env::set_var("GIT_WAIT_POLL", "1");
+            env::set_var("GIT_WAIT_POLL_INTERVAL_MS", "10");
+
+            let (tx, rx) = mpsc::channel::<Result<Vec<String>, String>>();
+            let handle = std::thread::spawn(move || {
+                let result =
+                    maybe_wait_for_index_lock(vec!["git".to_string(), "commit".to_string()]);
+                tx.send(result).unwrap();
+            });
+
+            let result = rx.recv_timeout(Duration::from_millis(50));
+            assert_eq!(result, Err(RecvTimeoutError::Timeout));
+
+            fs::remove_file(index_file).unwrap();
+
+            let result = rx.recv_timeout(Duration::from_millis(200));
+            assert!(result.unwrap().is_ok());
+
+            handle.join().unwrap();
+
+            env::remove_var("GIT_WAIT_POLL");
+            env::remove_var("GIT_WAIT_POLL_INTERVAL_MS");
+        });
+    }
+
+    #[test]
+    fn wait_by_polling_only_rescans_once_per_configured_interval() {
+        with_test_dir(|test_dir| {
+            fs::create_dir(&test_dir.path.join(".git")).unwrap();
+
+            let index_file = test_dir.path.join(".git/index.lock");
+            let _ = File::create(&index_file).unwrap();
+
+            env::set_var("GIT_WAIT_POLL", "1");
+            env::set_var("GIT_WAIT_POLL_INTERVAL_MS", "300");
+
+            let (tx, rx) = mpsc::channel::<Result<Vec<String>, String>>();
+            let handle = std::thread::spawn(move || {
+                let result =
+                    maybe_wait_for_index_lock(vec!["git".to_string(), "commit".to_string()]);
+                tx.send(result).unwrap();
+            });
+
+            // Give the wait loop a chance to take its first (pre-loop) scan, then
+            // remove the lock well before the next scheduled rescan at ~300ms.
+            std::thread::sleep(Duration::from_millis(20));
+            fs::remove_file(&index_file).unwrap();
+
+            let result = rx.recv_timeout(Duration::from_millis(150));
+            assert_eq!(result, Err(RecvTimeoutError::Timeout));
+
+            let result = rx.recv_timeout(Duration::from_millis(400));
+            assert!(result.unwrap().is_ok());
+
+            handle.join().unwrap();
+
+            env::remove_var("GIT_WAIT_POLL");
+            env::remove_var("GIT_WAIT_POLL_INTERVAL_MS");
+        });
+    }
+
+    #[test]
+    fn skips_waiting_for_read_only_command() {
+        with_test_dir(|test_dir| {
+            fs::create_dir(&test_dir.path.join(".git")).unwrap();
+
+            let index_file = test_dir.path.join(".git/index.lock");
+            let _ = File::create(&index_file).unwrap();
+
+            // "status" is a default read-only subcommand, so this should return
+            // immediately even though the lock is still present.
+            let result =
+                maybe_wait_for_index_lock(vec!["git".to_string(), "status".to_string()]);
+            assert!(result.is_ok());
+
+            fs::remove_file(index_file).unwrap();
+        });
+    }
+
+    #[test]
+    fn wait_for_env_var_overrides_default_skip_list() {
+        with_test_dir(|test_dir| {
+            fs::create_dir(&test_dir.path.join(".git")).unwrap();
+
+            let index_file = test_dir.path.join(".git/index.lock");
+            let _ = File::create(&index_file).unwrap();
+
+            env::set_var("GIT_WAIT_FOR", "status");
+
+            let (tx, rx) = mpsc::channel::<Result<Vec<String>, String>>();
+            let handle = std::thread::spawn(move || {
+                let result =
+                    maybe_wait_for_index_lock(vec!["git".to_string(), "status".to_string()]);
+                tx.send(result).unwrap();
+            });
+
+            let result = rx.recv_timeout(Duration::from_millis(100));
+            assert_eq!(result, Err(RecvTimeoutError::Timeout));
+
+            fs::remove_file(index_file).unwrap();
+
+            let result = rx.recv_timeout(Duration::from_millis(200));
+            assert!(result.unwrap().is_ok());
+
+            handle.join().unwrap();
+
+            env::remove_var("GIT_WAIT_FOR");
+        });
+    }
+
+    #[test]
+    fn locks_under_objects_dir_are_ignored() {
+        with_test_dir(|test_dir| {
+            let objects_dir = test_dir.path.join(".git/objects/ab");
+            fs::create_dir_all(&objects_dir).unwrap();
+            let _ = File::create(objects_dir.join("stale.lock")).unwrap();
+
+            assert!(find_lock_files(&test_dir.path.join(".git")).is_empty());
+        });
+    }
+
+    #[test]
+    fn git_wait_own_lock_file_is_not_treated_as_a_lock() {
+        with_test_dir(|test_dir| {
+            let git_dir = test_dir.path.join(".git");
+            fs::create_dir_all(&git_dir).unwrap();
+            let _ = File::create(git_dir.join("git-wait.lock")).unwrap();
+            let _ = File::create(git_dir.join("index.lock")).unwrap();
+
+            let locks = find_lock_files(&git_dir);
+            assert_eq!(locks, vec![git_dir.join("index.lock")]);
+        });
+    }
+
+    #[test]
+    fn resolves_git_file_to_linked_worktree() {
+        with_test_dir(|test_dir| {
+            let common_git_dir = test_dir.path.join(".git");
+            let worktree_meta_dir = common_git_dir.join("worktrees/feature");
+            fs::create_dir_all(&worktree_meta_dir).unwrap();
+            fs::write(worktree_meta_dir.join("commondir"), "../..\n").unwrap();
+
+            let git_file = test_dir.path.join("other-worktree/.git");
+            fs::create_dir_all(git_file.parent().unwrap()).unwrap();
+            fs::write(
+                &git_file,
+                format!("gitdir: {}\n", worktree_meta_dir.display()),
+            )
+            .unwrap();
+
+            let git_dirs = resolve_git_dirs(&git_file).unwrap();
+            assert_eq!(git_dirs.worktree_dir, worktree_meta_dir);
+            assert_eq!(git_dirs.common_dir, worktree_meta_dir.join("../.."));
+        });
+    }
+
+    #[test]
+    fn finds_locks_in_both_worktree_and_common_dir() {
+        with_test_dir(|test_dir| {
+            let common_dir = test_dir.path.join(".git");
+            let worktree_dir = common_dir.join("worktrees/feature");
+            fs::create_dir_all(&worktree_dir).unwrap();
+            let _ = File::create(worktree_dir.join("HEAD.lock")).unwrap();
+            let _ = File::create(common_dir.join("packed-refs.lock")).unwrap();
+
+            let git_dirs = GitDirs {
+                worktree_dir,
+                common_dir,
+            };
+            assert_eq!(git_dirs.find_lock_files().len(), 2);
+        });
+    }
+
+    #[test]
+    fn sibling_worktree_locks_are_not_counted_against_this_worktree() {
+        with_test_dir(|test_dir| {
+            let common_dir = test_dir.path.join(".git");
+            let worktree_a = common_dir.join("worktrees/feature-a");
+            let worktree_b = common_dir.join("worktrees/feature-b");
+            fs::create_dir_all(&worktree_a).unwrap();
+            fs::create_dir_all(&worktree_b).unwrap();
+            let _ = File::create(worktree_b.join("HEAD.lock")).unwrap();
+
+            let git_dirs = GitDirs {
+                worktree_dir: worktree_a,
+                common_dir,
+            };
+            assert!(git_dirs.find_lock_files().is_empty());
+        });
+    }
+
+    #[test]
+    fn watchable_subdirs_excludes_objects_and_worktrees() {
+        with_test_dir(|test_dir| {
+            let git_dir = test_dir.path.join(".git");
+            fs::create_dir_all(git_dir.join("objects/ab")).unwrap();
+            fs::create_dir_all(git_dir.join("worktrees/feature")).unwrap();
+            fs::create_dir_all(git_dir.join("refs/heads")).unwrap();
+
+            let dirs = watchable_subdirs(&git_dir);
+            assert!(dirs.contains(&git_dir));
+            assert!(dirs.contains(&git_dir.join("refs")));
+            assert!(dirs.contains(&git_dir.join("refs/heads")));
+            assert!(!dirs.iter().any(|d| d.starts_with(git_dir.join("objects"))));
+            assert!(!dirs.iter().any(|d| d.starts_with(git_dir.join("worktrees"))));
+        });
+    }
+
+    #[test]
+    fn describe_locks_single() {
+        let locks = vec![PathBuf::from(".git/index.lock")];
+        assert_eq!(describe_locks(&locks), "index.lock");
+    }
+
+    #[test]
+    fn describe_locks_names_first_and_counts_rest() {
+        let locks = vec![
+            PathBuf::from(".git/index.lock"),
+            PathBuf::from(".git/HEAD.lock"),
+            PathBuf::from(".git/packed-refs.lock"),
+        ];
+        assert_eq!(describe_locks(&locks), "index.lock (+2 more)");
+    }
+
     fn with_test_dir(block: fn(&TestDir) -> ()) {
         let _lock = test_file_lock.lock().unwrap();
         let test_dir = TestDir::new();